@@ -4,7 +4,144 @@ use std::ptr;
 use std::slice;
 use std::mem;
 
-use buf::{RdBuf, WrBuf};
+use buf::{RdBuf, WrBuf, RdBufVec, WrBufVec};
+
+const BITS: usize = 64;
+
+/// Byte-granular record of which parts of a buffer have been
+/// initialized, stored as one bit per byte.
+///
+/// This is the same trick as an interpreter's "undef mask": instead of
+/// a single high-watermark, track validity per byte so that disjoint
+/// regions filled by out-of-order or scatter completions can all be
+/// recorded independently.
+#[derive(Clone)]
+struct ValidMask {
+    bits: Vec<u64>,
+    len: usize,
+}
+
+impl ValidMask {
+    fn new(len: usize) -> ValidMask {
+        ValidMask { bits: vec![0u64; (len + BITS - 1) / BITS], len: len }
+    }
+
+    /// Mark `[base, base+len)` as valid.
+    ///
+    /// Operates a whole `u64` word at a time for the interior of the
+    /// range, only masking the partial head/tail words bit-by-bit, so
+    /// marking a multi-megabyte Direct IO buffer valid doesn't cost a
+    /// per-byte loop.
+    fn set_range(&mut self, base: usize, len: usize) {
+        assert!(base + len <= self.len);
+        if len == 0 { return; }
+        let end = base + len;
+        let first_word = base / BITS;
+        let last_word = (end - 1) / BITS;
+
+        if first_word == last_word {
+            self.bits[first_word] |= Self::word_mask(base % BITS, end % BITS);
+            return;
+        }
+
+        self.bits[first_word] |= Self::word_mask(base % BITS, 0);
+        for word in first_word + 1..last_word {
+            self.bits[word] = !0u64;
+        }
+        self.bits[last_word] |= Self::word_mask(0, end % BITS);
+    }
+
+    /// Test whether every byte in `[base, base+len)` is valid.
+    fn is_valid(&self, base: usize, len: usize) -> bool {
+        assert!(base + len <= self.len);
+        if len == 0 { return true; }
+        let end = base + len;
+        let first_word = base / BITS;
+        let last_word = (end - 1) / BITS;
+
+        if first_word == last_word {
+            let mask = Self::word_mask(base % BITS, end % BITS);
+            return self.bits[first_word] & mask == mask;
+        }
+
+        let head_mask = Self::word_mask(base % BITS, 0);
+        if self.bits[first_word] & head_mask != head_mask {
+            return false;
+        }
+        for word in first_word + 1..last_word {
+            if self.bits[word] != !0u64 {
+                return false;
+            }
+        }
+        let tail_mask = Self::word_mask(0, end % BITS);
+        self.bits[last_word] & tail_mask == tail_mask
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.bits[i / BITS] & (1u64 << (i % BITS)) != 0
+    }
+
+    /// Mask selecting bits `[from, to)` within a word, or all of it
+    /// if `to` is 0 and `from` is also 0; `to` of 0 means "through the
+    /// end of the word" rather than "through bit 0", matching how the
+    /// head/tail of a byte range maps onto whichever word it falls in.
+    fn word_mask(from: usize, to: usize) -> u64 {
+        let low = if from == 0 { !0u64 } else { !0u64 << from };
+        let high = if to == 0 { !0u64 } else { !(!0u64 << to) };
+        low & high
+    }
+
+    /// Length of the leading run of valid bytes, i.e. the largest `n`
+    /// such that `is_valid(0, n)` holds.
+    fn leading_valid_len(&self) -> usize {
+        let mut n = 0;
+        for &word in self.bits.iter() {
+            if word == !0u64 {
+                n += BITS;
+                continue;
+            }
+            // Stop at the first word with a gap; count its leading
+            // run of set low bits (at most BITS iterations, so this
+            // is still bounded work per word, not per buffer byte).
+            let mut w = word;
+            while w & 1 != 0 {
+                n += 1;
+                w >>= 1;
+            }
+            break;
+        }
+        if n > self.len { self.len } else { n }
+    }
+
+    fn ranges(&self) -> ValidRanges {
+        ValidRanges { mask: self, pos: 0 }
+    }
+}
+
+/// Iterator over the coalesced `(start, len)` intervals of valid bytes
+/// in a `ValidMask`, in ascending order.
+struct ValidRanges<'a> {
+    mask: &'a ValidMask,
+    pos: usize,
+}
+
+impl<'a> Iterator for ValidRanges<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        while self.pos < self.mask.len && !self.mask.bit(self.pos) {
+            self.pos += 1;
+        }
+        if self.pos >= self.mask.len {
+            return None;
+        }
+        let start = self.pos;
+        while self.pos < self.mask.len && self.mask.bit(self.pos) {
+            self.pos += 1;
+        }
+        Some((start, self.pos - start))
+    }
+}
 
 /// Allocate and manage buffers with fixed memory alignment.
 ///
@@ -16,7 +153,7 @@ pub struct AlignedBuf {
     buf: *mut u8,               // pointer to allocated memory
     align: usize,               // alignment of buffer
     len: usize,                 // length of allocated memory
-    valid: usize,               // length of valid/initialized memory
+    valid: ValidMask,           // bitmask of initialized bytes
 }
 
 unsafe impl Send for AlignedBuf {}
@@ -43,7 +180,7 @@ impl AlignedBuf {
         if p.is_null() {
             None
         } else {
-            Some(AlignedBuf { buf: mem::transmute(p), len: sz, valid: 0, align: align })
+            Some(AlignedBuf { buf: mem::transmute(p), len: sz, valid: ValidMask::new(sz), align: align })
         }
     }
 
@@ -54,7 +191,8 @@ impl AlignedBuf {
                 None => None,
                 Some(mut b) => {
                     ptr::write_bytes(b.buf, 0, b.len);
-                    b.valid = b.len;
+                    let len = b.len;
+                    b.valid.set_range(0, len);
                     Some(b)
                 }
             }
@@ -72,7 +210,8 @@ impl AlignedBuf {
                         assert!(b.len > data.len());
                         ptr::write_bytes((b.buf as usize + data.len()) as *mut u8, 0, b.len - data.len())
                     };
-                    b.valid = b.len;
+                    let len = b.len;
+                    b.valid.set_range(0, len);
                     Some(b)
                 }
             }
@@ -80,7 +219,7 @@ impl AlignedBuf {
     }
 
     pub fn as_slice(&self) -> &[u8] { self.wrbuf() }
-    
+
     pub unsafe fn as_ptr(&self) -> *const u8 {
         self.buf as *const u8
     }
@@ -90,7 +229,40 @@ impl AlignedBuf {
     }
 
     pub fn len(&self) -> usize { self.len }
-    pub fn valid(&self) -> usize { self.valid }
+
+    /// Length of the leading run of valid (initialized) bytes.
+    pub fn valid(&self) -> usize { self.valid.leading_valid_len() }
+
+    /// Test whether `[base, base+len)` is entirely valid.
+    pub fn is_valid(&self, base: usize, len: usize) -> bool {
+        self.valid.is_valid(base, len)
+    }
+
+    /// Iterate over the coalesced `(start, len)` ranges of valid bytes,
+    /// in ascending order.
+    pub fn valid_ranges<'a>(&'a self) -> Box<Iterator<Item=(usize, usize)> + 'a> {
+        Box::new(self.valid.ranges())
+    }
+
+    /// Attempt to clone the buffer, copying the valid portion (and the
+    /// validity mask) of it from the source. Returns `None` rather
+    /// than aborting if the allocation fails.
+    pub fn try_clone(&self) -> Option<AlignedBuf> {
+        unsafe {
+            match AlignedBuf::alloc_uninit(self.len, self.align) {
+                None => None,
+                Some(mut b) => {
+                    for (start, len) in self.valid.ranges() {
+                        ptr::copy_nonoverlapping((self.buf as usize + start) as *const u8,
+                                                  (b.buf as usize + start) as *mut u8,
+                                                  len);
+                    }
+                    b.valid = self.valid.clone();
+                    Some(b)
+                }
+            }
+        }
+    }
 }
 
 impl Drop for AlignedBuf {
@@ -104,18 +276,9 @@ impl Clone for AlignedBuf {
     /// source. The non-valid part of the result has undefined
     /// contents which may be different from the source.
     fn clone(&self) -> AlignedBuf {
-        assert!(self.valid <= self.len);
-        unsafe {
-            match AlignedBuf::alloc_uninit(self.len, self.align) {
-                None => panic!("clone failed"),
-                Some(mut b) => {
-                    if b.valid > 0 {
-                        ptr::copy_nonoverlapping(self.buf as *const u8, b.buf, b.valid);
-                        b.valid = self.valid
-                    };
-                    b
-                }
-            }
+        match self.try_clone() {
+            None => panic!("clone failed"),
+            Some(b) => b,
         }
     }
 }
@@ -124,25 +287,58 @@ impl RdBuf for AlignedBuf {
     /// Return a writable slice to the whole buffer; it may not be
     /// initialized, and so should be treated as write-only.
     fn rdbuf<'a>(&'a mut self) -> &'a mut [u8] {
-        assert!(self.valid <= self.len);
         unsafe { slice::from_raw_parts_mut(self.buf, self.len) }
     }
 
-    /// Update the valid portion of the buffer.
+    /// Update the valid portion of the buffer to mark `[base, base+len)`
+    /// as initialized. Unlike the old high-watermark scheme, this
+    /// records the range directly, so disjoint regions filled by
+    /// out-of-order completions are each tracked independently.
     fn rdupdate(&mut self, base: usize, len: usize) {
-        assert!(self.valid <= self.len);
-        if base <= self.valid && base+len > self.valid {
-            assert!(base+len <= self.len);
-            self.valid = base+len;
-        }
+        if len == 0 { return; }
+        assert!(base + len <= self.len);
+        self.valid.set_range(base, len);
     }
 }
 
 impl WrBuf for AlignedBuf {
-    /// Return a read-only slice of the valid portion of the buffer.
+    /// Return a read-only slice of the leading valid portion of the
+    /// buffer, i.e. the longest prefix that is fully initialized.
     fn wrbuf<'a>(&'a self) -> &'a [u8] {
-        assert!(self.valid <= self.len);
-        unsafe { slice::from_raw_parts_mut(self.buf, self.valid) }
+        let valid = self.valid.leading_valid_len();
+        unsafe { slice::from_raw_parts_mut(self.buf, valid) }
+    }
+}
+
+impl RdBufVec for [AlignedBuf] {
+    fn rdbuf_vec<'a>(&'a mut self) -> Vec<&'a mut [u8]> {
+        self.iter_mut().map(|b| b.rdbuf()).collect()
+    }
+
+    fn rdupdate_vec(&mut self, seg: usize, base: usize, len: usize) {
+        self[seg].rdupdate(base, len);
+    }
+}
+
+impl WrBufVec for [AlignedBuf] {
+    fn wrbuf_vec<'a>(&'a self) -> Vec<&'a [u8]> {
+        self.iter().map(|b| b.wrbuf()).collect()
+    }
+}
+
+impl RdBufVec for Vec<AlignedBuf> {
+    fn rdbuf_vec<'a>(&'a mut self) -> Vec<&'a mut [u8]> {
+        (&mut self[..]).rdbuf_vec()
+    }
+
+    fn rdupdate_vec(&mut self, seg: usize, base: usize, len: usize) {
+        (&mut self[..]).rdupdate_vec(seg, base, len)
+    }
+}
+
+impl WrBufVec for Vec<AlignedBuf> {
+    fn wrbuf_vec<'a>(&'a self) -> Vec<&'a [u8]> {
+        (&self[..]).wrbuf_vec()
     }
 }
 
@@ -168,4 +364,46 @@ mod test {
         let p = alloc(17, 16);
         assert_eq!(p.as_slice().len(), 32);
     }
+
+    #[test]
+    fn disjoint_valid_ranges() {
+        use buf::RdBuf;
+        let mut p = unsafe { AlignedBuf::alloc_uninit(64, 16).unwrap() };
+
+        p.rdupdate(32, 8);
+        p.rdupdate(0, 8);
+
+        assert!(p.is_valid(0, 8));
+        assert!(p.is_valid(32, 8));
+        assert!(!p.is_valid(0, 16));
+        assert_eq!(p.valid(), 8);
+
+        let ranges: Vec<(usize, usize)> = p.valid_ranges().collect();
+        assert_eq!(ranges, vec![(0, 8), (32, 8)]);
+
+        p.rdupdate(8, 24);
+        assert!(p.is_valid(0, 40));
+        assert_eq!(p.valid(), 40);
+    }
+
+    #[test]
+    fn valid_range_spanning_words() {
+        // 256 bytes covers four 64-bit words of the mask; exercise a
+        // range that starts and ends mid-word on both sides, to cover
+        // the word-at-a-time path in `set_range`/`is_valid` rather
+        // than just the single-word case above.
+        use buf::RdBuf;
+        let mut p = unsafe { AlignedBuf::alloc_uninit(256, 16).unwrap() };
+
+        p.rdupdate(40, 160);
+
+        assert!(!p.is_valid(39, 160));
+        assert!(!p.is_valid(40, 161));
+        assert!(p.is_valid(40, 160));
+        assert!(p.is_valid(64, 64));
+        assert_eq!(p.valid(), 0);
+
+        p.rdupdate(0, 40);
+        assert_eq!(p.valid(), 200);
+    }
 }
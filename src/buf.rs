@@ -15,6 +15,27 @@ pub trait WrBuf {
     fn wrbuf<'a>(&'a self) -> &'a [u8];
 }
 
+/// Trait for types implementing a vectored (scatter) read buffer: an
+/// ordered list of segments, each independently fillable, for use
+/// with `preadv`-style submission.
+pub trait RdBufVec {
+    /// Return a mutable list of segments to read into, in order; each
+    /// may be only partially filled.
+    fn rdbuf_vec<'a>(&'a mut self) -> Vec<&'a mut [u8]>;
+
+    /// Called to indicate that the read updated segment `seg`, from
+    /// [`base` .. `base`+`len`) within that segment.
+    fn rdupdate_vec(&mut self, _seg: usize, _base: usize, _len: usize) {}
+}
+
+/// Trait for types implementing a vectored (gather) write buffer: an
+/// ordered list of segments providing the source data for a
+/// `pwritev`-style submission.
+pub trait WrBufVec {
+    /// Return the list of segments making up the source data, in order.
+    fn wrbuf_vec<'a>(&'a self) -> Vec<&'a [u8]>;
+}
+
 /// Wrapper for plain [u8] implementing RdBuf and WrBuf traits.
 pub type Buf<'b> = &'b mut [u8];
 
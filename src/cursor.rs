@@ -0,0 +1,176 @@
+//! A typed, endian-aware cursor over `RdBuf`/`WrBuf` buffers, for
+//! serializing fixed-layout records into (or out of) Direct IO
+//! buffers without hand-writing byte offsets.
+use buf::{RdBuf, WrBuf};
+
+/// Returned when a read or write would run past the end of the
+/// underlying buffer.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Overflow;
+
+pub type Result<T> = ::std::result::Result<T, Overflow>;
+
+/// Tracks a position within a `RdBuf`/`WrBuf` buffer and offers
+/// typed, bounds-checked big/little-endian integer accessors.
+///
+/// Reading requires the underlying buffer to implement `WrBuf` (its
+/// initialized contents are the source of the read); writing requires
+/// `RdBuf`, and each write calls `rdupdate` so the buffer's validity
+/// tracking knows exactly which bytes were just initialized.
+///
+/// Reads are bounds-checked against `WrBuf::wrbuf()`'s length, not the
+/// buffer's full allocated size. For `AlignedBuf`, `wrbuf()` only
+/// covers the *leading* fully-valid prefix, so a cursor can't read
+/// past the first gap left by a scatter completion that filled later
+/// segments out of order; read the valid ranges you need in order, or
+/// wait until they've coalesced into that leading prefix.
+pub struct Cursor<B> {
+    buf: B,
+    pos: usize,
+}
+
+impl<B> Cursor<B> {
+    /// Wrap `buf` in a cursor starting at position 0.
+    pub fn new(buf: B) -> Cursor<B> {
+        Cursor { buf: buf, pos: 0 }
+    }
+
+    /// Current position.
+    pub fn position(&self) -> usize { self.pos }
+
+    /// Move to `pos`, without any bounds check; the next access will
+    /// fail if it's out of range.
+    pub fn set_position(&mut self, pos: usize) { self.pos = pos; }
+
+    /// Unwrap the cursor, discarding the position.
+    pub fn into_inner(self) -> B { self.buf }
+}
+
+impl<B: WrBuf> Cursor<B> {
+    /// Read `len` bytes, advancing the position. Bounded by
+    /// `buf.wrbuf().len()` — see the leading-prefix caveat on
+    /// `Cursor` for `AlignedBuf`.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let buf = self.buf.wrbuf();
+        if self.pos + len > buf.len() {
+            return Err(Overflow);
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&buf[start .. self.pos])
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(try!(self.read_bytes(1))[0])
+    }
+
+    pub fn read_u16_le(&mut self) -> Result<u16> {
+        let b = try!(self.read_bytes(2));
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn read_u16_be(&mut self) -> Result<u16> {
+        let b = try!(self.read_bytes(2));
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn read_u32_le(&mut self) -> Result<u32> {
+        let b = try!(self.read_bytes(4));
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn read_u32_be(&mut self) -> Result<u32> {
+        let b = try!(self.read_bytes(4));
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn read_u64_le(&mut self) -> Result<u64> {
+        let b = try!(self.read_bytes(8));
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    pub fn read_u64_be(&mut self) -> Result<u64> {
+        let b = try!(self.read_bytes(8));
+        Ok(u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+}
+
+impl<B: RdBuf> Cursor<B> {
+    /// Write `data`, advancing the position and marking the written
+    /// span valid via `rdupdate`.
+    pub fn write_bytes(&mut self, data: &[u8]) -> Result<()> {
+        let len = data.len();
+        {
+            let buf = self.buf.rdbuf();
+            if self.pos + len > buf.len() {
+                return Err(Overflow);
+            }
+            buf[self.pos .. self.pos + len].copy_from_slice(data);
+        }
+        let base = self.pos;
+        self.pos += len;
+        self.buf.rdupdate(base, len);
+        Ok(())
+    }
+
+    pub fn write_u8(&mut self, v: u8) -> Result<()> {
+        self.write_bytes(&[v])
+    }
+
+    pub fn write_u16_le(&mut self, v: u16) -> Result<()> {
+        self.write_bytes(&v.to_le_bytes())
+    }
+
+    pub fn write_u16_be(&mut self, v: u16) -> Result<()> {
+        self.write_bytes(&v.to_be_bytes())
+    }
+
+    pub fn write_u32_le(&mut self, v: u32) -> Result<()> {
+        self.write_bytes(&v.to_le_bytes())
+    }
+
+    pub fn write_u32_be(&mut self, v: u32) -> Result<()> {
+        self.write_bytes(&v.to_be_bytes())
+    }
+
+    pub fn write_u64_le(&mut self, v: u64) -> Result<()> {
+        self.write_bytes(&v.to_le_bytes())
+    }
+
+    pub fn write_u64_be(&mut self, v: u64) -> Result<()> {
+        self.write_bytes(&v.to_be_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cursor;
+    use aligned::AlignedBuf;
+
+    #[test]
+    fn roundtrip() {
+        let buf = unsafe { AlignedBuf::alloc_uninit(32, 16).unwrap() };
+        let mut w = Cursor::new(buf);
+
+        w.write_u16_le(0x1234).unwrap();
+        w.write_u32_be(0xdeadbeef).unwrap();
+        w.write_u8(0xff).unwrap();
+
+        let buf = w.into_inner();
+        let mut r = Cursor::new(buf);
+
+        assert_eq!(r.read_u16_le().unwrap(), 0x1234);
+        assert_eq!(r.read_u32_be().unwrap(), 0xdeadbeef);
+        assert_eq!(r.read_u8().unwrap(), 0xff);
+    }
+
+    #[test]
+    fn overflow() {
+        // Allocation rounds up to the alignment, so this buffer is 16
+        // bytes; a u32 written at 14 runs 2 bytes past the end.
+        let buf = unsafe { AlignedBuf::alloc_uninit(16, 16).unwrap() };
+        let mut w = Cursor::new(buf);
+        w.set_position(14);
+        assert!(w.write_u32_le(1).is_err());
+    }
+}
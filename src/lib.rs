@@ -8,6 +8,7 @@ mod pool;
 pub mod raw;
 pub mod directio;
 pub mod aligned;
+pub mod cursor;
 
 /// Wrapper for file offset
 pub type Offset = u64;
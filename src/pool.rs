@@ -1,5 +1,6 @@
 extern crate std;
 
+use std::collections::TryReserveError;
 use std::ops::{Index,IndexMut};
 
 pub enum Slot<T> {
@@ -25,6 +26,18 @@ impl<T> Pool<T> {
                next: 0 }
     }
 
+    /// Create a new pool with a given size, returning an error rather
+    /// than aborting the process if the backing allocation fails.
+    pub fn try_new(size: usize) -> Result<Pool<T>, TryReserveError> {
+        assert!(size > 0);
+        let mut pool = Vec::new();
+        try!(pool.try_reserve(size));
+        for i in 1 .. size + 1 {
+            pool.push(Slot::Free(i));
+        }
+        Ok(Pool { pool: pool, freelist: (size - 1) as isize, used: 0, next: 0 })
+    }
+
     /// Allocate an index in the pool. Returns None if the Pool is all used.
     pub fn allocidx(&mut self, init: T) -> Result<usize, T> {
         let idx = self.next;
@@ -74,6 +87,22 @@ impl<T> Pool<T> {
     /// Return number of remaining unused entries.
     #[allow(dead_code)]
     pub fn avail(&self) -> usize { self.limit() - self.used() }
+
+    /// Return the entry at `idx`, or `None` if it's out of range or free.
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        match self.pool.get(idx) {
+            Some(&Slot::Alloc(ref t)) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// Return the entry at `idx`, or `None` if it's out of range or free.
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
+        match self.pool.get_mut(idx) {
+            Some(&mut Slot::Alloc(ref mut t)) => Some(t),
+            _ => None,
+        }
+    }
 }
 
 impl<T> Index<usize> for Pool<T> {
@@ -257,6 +286,48 @@ mod test {
         unsafe { p.freeptr(&foo as *const isize) };
     }
 
+    #[test]
+    fn try_new_matches_new() {
+        let mut p = Pool::try_new(4).unwrap();
+
+        assert_eq!(p.limit(), 4);
+        assert_eq!(p.used(), 0);
+        assert_eq!(p.avail(), 4);
+
+        for i in 0..4 {
+            let idx = p.allocidx(i);
+
+            assert_eq!(p.used(), (i + 1) as usize);
+            assert!(idx.is_ok());
+            assert!(p[idx.ok().unwrap()] == i);
+        }
+
+        assert!(p.avail() == 0);
+        let idx = p.allocidx(10);
+        assert!(p.avail() == 0);
+        assert!(idx.is_err());
+
+        assert_eq!(0, p.freeidx(0));
+        assert_eq!(Ok(0), p.allocidx(2));
+    }
+
+    #[test]
+    fn get_and_get_mut() {
+        let mut p = Pool::new(4);
+
+        assert!(p.get(0).is_none());
+        assert!(p.get_mut(0).is_none());
+        assert!(p.get(4).is_none());
+
+        let idx = p.allocidx(42).ok().unwrap();
+        assert_eq!(p.get(idx), Some(&42));
+        assert_eq!(p.get_mut(idx), Some(&mut 42));
+
+        p.freeidx(idx);
+        assert!(p.get(idx).is_none());
+        assert!(p.get_mut(idx).is_none());
+    }
+
     #[test]
     #[should_panic]
     fn badptr2() {
@@ -0,0 +1,572 @@
+//! Low-level submission/completion context built on top of the
+//! `aioabi` bindings and the iocb `Pool`.
+extern crate std;
+extern crate libc;
+
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::io::RawFd;
+use std::mem;
+
+use aioabi;
+use aioabi::{Struct_iocb, Struct_io_event, Struct_iovec, io_context_t, Iocmd, IOCB_FLAG_RESFD, timespec};
+use pool::Pool;
+
+/// Errors returned by the raw submission/completion context.
+#[derive(Debug)]
+pub enum Error {
+    Setup(i32),
+    Submit(i32),
+    GetEvents(i32),
+}
+
+/// A single outstanding or queued I/O operation.
+struct Op {
+    iocb: Box<Struct_iocb>,
+    fd: RawFd,
+    start: u64,
+    end: u64,
+    write: bool,
+    submitted: bool,
+    // For vectored reads, the length of each segment in submission
+    // order, so a completion's single byte count can be distributed
+    // across them. Kept alongside the iovec array (below), which must
+    // outlive the operation since the kernel reads `aio_buf` as a
+    // pointer to it.
+    seg_lens: Option<Vec<usize>>,
+    iovecs: Option<Vec<Struct_iovec>>,
+}
+
+/// A completed operation, as reaped from `Context::getevents`.
+pub struct Completion {
+    pub idx: usize,
+    pub res: i64,
+    /// For vectored reads, how many of the `res` bytes landed in each
+    /// segment, in submission order. Callers pass these to
+    /// `RdBufVec::rdupdate_vec` to mark the right ranges valid.
+    pub seg_lens: Option<Vec<usize>>,
+}
+
+/// Split `total` bytes across `lens` in order, as the kernel would
+/// have filled them.
+fn distribute(mut total: usize, lens: &[usize]) -> Vec<usize> {
+    lens.iter().map(|&len| {
+        let n = if total < len { total } else { len };
+        total -= n;
+        n
+    }).collect()
+}
+
+/// Tracks outstanding I/O by file region, kept free of any syscall so
+/// the scheduling policy can be unit tested in isolation from
+/// `Context`.
+///
+/// Every in-flight op (read or write) is entered here, keyed by its
+/// start offset within its fd and its pool index (two in-flight ops
+/// can legitimately share a start offset, e.g. two overlapping reads,
+/// so the index disambiguates them), alongside its end offset and
+/// whether it's a write. Two in-flight reads never conflict with each
+/// other, but a write conflicts with anything overlapping it (read or
+/// write) and a read conflicts with any overlapping in-flight write,
+/// since either ordering of out-of-order completion could otherwise
+/// let a read observe a write that hadn't happened yet in program
+/// order, or miss one that had.
+struct OverlapTracker {
+    inflight: HashMap<RawFd, BTreeMap<(u64, usize), (u64, bool)>>,
+}
+
+impl OverlapTracker {
+    fn new() -> OverlapTracker {
+        OverlapTracker { inflight: HashMap::new() }
+    }
+
+    /// Would an op spanning `[start, end)` on `fd`, of kind `write`,
+    /// conflict with anything currently in-flight?
+    fn conflicts(&self, fd: RawFd, start: u64, end: u64, write: bool) -> bool {
+        let map = match self.inflight.get(&fd) {
+            None => return false,
+            Some(m) => m,
+        };
+        // Unlike the write-only map this replaced, entries here can
+        // themselves overlap (two in-flight reads may), so there's no
+        // sorted-by-end invariant to exploit; just scan every entry
+        // whose start is before our end. Keys are (start, idx) tuples,
+        // compared lexicographically, so `.. (end, 0)` selects exactly
+        // the entries with a start offset less than `end`, regardless
+        // of idx (every idx is >= 0).
+        for (&(ostart, _), &(oend, owrite)) in map.range(.. (end, 0)) {
+            if oend <= start || ostart >= end {
+                continue;
+            }
+            if write || owrite {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn insert(&mut self, fd: RawFd, start: u64, idx: usize, end: u64, write: bool) {
+        self.inflight.entry(fd).or_insert_with(BTreeMap::new).insert((start, idx), (end, write));
+    }
+
+    fn remove(&mut self, fd: RawFd, start: u64, idx: usize) {
+        if let Some(map) = self.inflight.get_mut(&fd) {
+            map.remove(&(start, idx));
+        }
+    }
+}
+
+/// Release as many `pending` ops (in the order they were queued) as
+/// no longer conflict with anything in `overlap`, reserving each
+/// released op's range in `overlap` as it goes. Reserving incrementally
+/// (rather than deciding the whole batch against the pre-release
+/// snapshot of `overlap`) matters when two pending ops conflict with
+/// each other as well as with the op that just completed: only the
+/// first one queued is released, and the second stays pending behind
+/// it instead of being released concurrently alongside it. Kept as a
+/// free function, separate from `Context`, so the release policy can
+/// be tested without a real AIO context.
+fn partition_pending(overlap: &mut OverlapTracker, pending: Vec<(usize, RawFd, u64, u64, bool)>)
+                      -> (Vec<usize>, Vec<(usize, RawFd, u64, u64, bool)>) {
+    let mut ready = Vec::new();
+    let mut still_pending = Vec::new();
+    for (idx, fd, start, end, write) in pending {
+        if overlap.conflicts(fd, start, end, write) {
+            still_pending.push((idx, fd, start, end, write));
+        } else {
+            overlap.insert(fd, start, idx, end, write);
+            ready.push(idx);
+        }
+    }
+    (ready, still_pending)
+}
+
+/// Wraps a Linux AIO context, allocating iocbs from a `Pool` and
+/// serializing operations that would otherwise overlap an in-flight
+/// one on the same file region.
+///
+/// `io_submit`ed operations can complete in any order, so two
+/// concurrently-submitted overlapping writes (or a write and a read
+/// that overlap) could be seen by the kernel out of program order. To
+/// avoid that, any new operation that overlaps an in-flight op it
+/// isn't compatible with (see `OverlapTracker`) is queued rather than
+/// submitted, and is only submitted once the conflicting op completes.
+pub struct Context {
+    ctx: io_context_t,
+    ops: Pool<Op>,
+    overlap: OverlapTracker,
+    // Pool indices of ops that are queued behind a conflict.
+    pending: Vec<usize>,
+    // eventfd to notify on completion, set via `set_resfd`.
+    resfd: Option<RawFd>,
+}
+
+impl Context {
+    /// Set up a new AIO context with room for `maxevents` concurrent
+    /// iocbs.
+    pub fn new(maxevents: usize) -> Result<Context, Error> {
+        let mut ctx: io_context_t = unsafe { mem::zeroed() };
+        let rc = unsafe { aioabi::io_setup(maxevents as i32, &mut ctx) };
+        if rc < 0 {
+            return Err(Error::Setup(-rc));
+        }
+        Ok(Context {
+            ctx: ctx,
+            ops: Pool::new(maxevents),
+            overlap: OverlapTracker::new(),
+            pending: Vec::new(),
+            resfd: None,
+        })
+    }
+
+    /// Wire AIO completions into an eventfd, for use with epoll/poll
+    /// based reactors instead of blocking in `io_getevents`. `fd` is
+    /// an eventfd created by the caller; every iocb submitted after
+    /// this call has `IOCB_FLAG_RESFD` set and `aio_resfd` pointed at
+    /// it, so the kernel bumps the eventfd's counter on each
+    /// completion. Pair with `drain_resfd` once `fd` reports readable.
+    pub fn set_resfd(&mut self, fd: RawFd) {
+        self.resfd = Some(fd);
+    }
+
+    /// If `set_resfd` has been called, set `IOCB_FLAG_RESFD` and
+    /// `aio_resfd` on `iocb` so its completion is signalled there.
+    fn set_resfd_fields(&self, iocb: &mut Struct_iocb) {
+        if let Some(fd) = self.resfd {
+            iocb.aio_flags |= IOCB_FLAG_RESFD;
+            iocb.aio_resfd = fd as u32;
+        }
+    }
+
+    fn new_op(&mut self, fd: RawFd, offset: u64, len: u64, buf: *mut u8, write: bool) -> Result<usize, Error> {
+        let mut iocb: Box<Struct_iocb> = Box::new(Default::default());
+        iocb.aio_fildes = fd as u32;
+        iocb.aio_buf = buf as u64;
+        iocb.aio_count = len;
+        iocb.aio_offset = offset as i64;
+        iocb.aio_lio_opcode = if write { Iocmd::IoCmdPwrite as u16 } else { Iocmd::IoCmdPread as u16 };
+        self.set_resfd_fields(&mut iocb);
+
+        let op = Op {
+            iocb: iocb, fd: fd, start: offset, end: offset + len, write: write,
+            submitted: false, seg_lens: None, iovecs: None,
+        };
+        match self.ops.allocidx(op) {
+            Ok(idx) => Ok(idx),
+            Err(_) => Err(Error::Submit(0)),
+        }
+    }
+
+    /// Build a vectored op from `segs`, a list of `(ptr, len)` pairs in
+    /// submission order. `track_lens` should be true for reads, so the
+    /// segment lengths are retained for distributing the completion's
+    /// byte count; writes don't need it since nothing downstream cares
+    /// how a write's byte count split across segments.
+    fn new_vec_op(&mut self, fd: RawFd, offset: u64, segs: Vec<(*mut u8, usize)>,
+                  write: bool, track_lens: bool) -> Result<usize, Error> {
+        let total: usize = segs.iter().map(|&(_, len)| len).sum();
+        let iovecs: Vec<Struct_iovec> = segs.iter()
+            .map(|&(ptr, len)| Struct_iovec { iov_base: ptr, iov_len: len })
+            .collect();
+
+        let mut iocb: Box<Struct_iocb> = Box::new(Default::default());
+        iocb.aio_fildes = fd as u32;
+        iocb.aio_buf = iovecs.as_ptr() as u64;
+        iocb.aio_count = iovecs.len() as u64;
+        iocb.aio_offset = offset as i64;
+        iocb.aio_lio_opcode = if write { Iocmd::IoCmdPwritev as u16 } else { Iocmd::IoCmdPreadv as u16 };
+        self.set_resfd_fields(&mut iocb);
+
+        let op = Op {
+            iocb: iocb, fd: fd, start: offset, end: offset + total as u64, write: write,
+            submitted: false,
+            seg_lens: if track_lens { Some(segs.iter().map(|&(_, len)| len).collect()) } else { None },
+            iovecs: Some(iovecs),
+        };
+        match self.ops.allocidx(op) {
+            Ok(idx) => Ok(idx),
+            Err(_) => Err(Error::Submit(0)),
+        }
+    }
+
+    /// Actually hand the op at `idx` to the kernel. Callers are
+    /// responsible for the overlap tracker: this only touches the
+    /// iocb and the AIO context.
+    fn do_submit(&mut self, idx: usize) -> Result<(), Error> {
+        let op = &mut self.ops[idx];
+        op.iocb.data = idx as u64;
+        let mut iocbp = &mut *op.iocb as *mut Struct_iocb;
+        let rc = unsafe { aioabi::io_submit(self.ctx, 1, &mut iocbp) };
+        if rc < 0 {
+            return Err(Error::Submit(-rc));
+        }
+        op.submitted = true;
+        Ok(())
+    }
+
+    fn queue_or_submit(&mut self, idx: usize) -> Result<usize, Error> {
+        let (fd, start, end, write) = {
+            let op = &self.ops[idx];
+            (op.fd, op.start, op.end, op.write)
+        };
+        if self.overlap.conflicts(fd, start, end, write) {
+            self.pending.push(idx);
+            Ok(idx)
+        } else {
+            self.overlap.insert(fd, start, idx, end, write);
+            match self.do_submit(idx) {
+                Ok(()) => Ok(idx),
+                Err(e) => {
+                    self.overlap.remove(fd, start, idx);
+                    self.ops.freeidx(idx);
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Submit a read of `len` bytes at `offset` in `fd` into `buf`.
+    /// `buf` must stay valid and unmoved until the operation
+    /// completes. Returns the pool index identifying the operation.
+    pub unsafe fn submit_read(&mut self, fd: RawFd, offset: u64, buf: *mut u8, len: u64) -> Result<usize, Error> {
+        let idx = try!(self.new_op(fd, offset, len, buf, false));
+        self.queue_or_submit(idx)
+    }
+
+    /// Submit a write of `len` bytes at `offset` in `fd` from `buf`.
+    /// `buf` must stay valid and unmoved until the operation
+    /// completes. Returns the pool index identifying the operation.
+    pub unsafe fn submit_write(&mut self, fd: RawFd, offset: u64, buf: *const u8, len: u64) -> Result<usize, Error> {
+        let idx = try!(self.new_op(fd, offset, len, buf as *mut u8, true));
+        self.queue_or_submit(idx)
+    }
+
+    /// Submit a scatter read of `segs` (pointer/length pairs, in
+    /// order) at `offset` in `fd`, via `preadv`. Each segment must
+    /// stay valid and unmoved until the operation completes. The
+    /// returned `Completion::seg_lens` tells the caller how the
+    /// resulting byte count split across segments.
+    pub unsafe fn submit_readv(&mut self, fd: RawFd, offset: u64, segs: Vec<(*mut u8, usize)>) -> Result<usize, Error> {
+        let idx = try!(self.new_vec_op(fd, offset, segs, false, true));
+        self.queue_or_submit(idx)
+    }
+
+    /// Submit a gather write of `segs` (pointer/length pairs, in
+    /// order) at `offset` in `fd`, via `pwritev`. Each segment must
+    /// stay valid and unmoved until the operation completes.
+    pub unsafe fn submit_writev(&mut self, fd: RawFd, offset: u64, segs: Vec<(*const u8, usize)>) -> Result<usize, Error> {
+        let segs = segs.into_iter().map(|(p, len)| (p as *mut u8, len)).collect();
+        let idx = try!(self.new_vec_op(fd, offset, segs, true, false));
+        self.queue_or_submit(idx)
+    }
+
+    /// Reap up to `max` completions, blocking for at least `min_nr` of
+    /// them. Frees each completed op's pool slot, removes its range
+    /// from the overlap tracker, and submits any now-unblocked pending
+    /// ops.
+    pub fn getevents(&mut self, min_nr: usize, max: usize) -> Result<Vec<Completion>, Error> {
+        self.getevents_with_timeout(min_nr, max, std::ptr::null_mut())
+    }
+
+    /// Read the 8-byte completion counter off an eventfd wired up via
+    /// `set_resfd`. Split out of `drain_resfd` so the plain `read(2)`
+    /// round-trip can be tested without a live AIO context.
+    fn read_resfd_counter(fd: RawFd) -> Result<u64, Error> {
+        let mut counter: u64 = 0;
+        let n = unsafe {
+            libc::read(fd, &mut counter as *mut u64 as *mut libc::c_void, mem::size_of::<u64>())
+        };
+        if n != mem::size_of::<u64>() as isize {
+            return Err(Error::GetEvents(-1));
+        }
+        Ok(counter)
+    }
+
+    /// As `getevents`, but drains exactly the `count` completions an
+    /// eventfd set via `set_resfd` reported as pending, with a
+    /// zero-timeout `io_getevents` call since they're already known to
+    /// be ready.
+    ///
+    /// Call this after the eventfd reports readable: it reads the
+    /// 8-byte completion counter to learn `count`, then drains it.
+    /// `eventfd(2)` only reports the fd readable once the counter is
+    /// nonzero, but if something else drains it between the caller
+    /// noticing readability and this call running, the counter read
+    /// here comes back `0`; treat that as a spurious wakeup with
+    /// nothing to reap rather than issuing a pointless zero-count
+    /// `io_getevents` call.
+    pub fn drain_resfd(&mut self) -> Result<Vec<Completion>, Error> {
+        let fd = self.resfd.expect("drain_resfd called without set_resfd");
+        let counter = try!(Context::read_resfd_counter(fd));
+        if counter == 0 {
+            return Ok(Vec::new());
+        }
+        let mut zero = timespec { tv_sec: 0, tv_nsec: 0 };
+        self.getevents_with_timeout(counter as usize, counter as usize, &mut zero)
+    }
+
+    fn getevents_with_timeout(&mut self, min_nr: usize, max: usize, timeout: *mut timespec) -> Result<Vec<Completion>, Error> {
+        let mut events: Vec<Struct_io_event> = (0 .. max).map(|_| Default::default()).collect();
+        let rc = unsafe {
+            aioabi::io_getevents(self.ctx, min_nr as i64, max as i64,
+                                  events.as_mut_ptr(), timeout)
+        };
+        if rc < 0 {
+            return Err(Error::GetEvents(-rc));
+        }
+        let n = rc as usize;
+        Ok(events[..n].iter().map(|event| self.complete(event.data as usize, event.res)).collect())
+    }
+
+    /// Handle completion of the op at `idx`: free its slot, clear its
+    /// entry from the overlap tracker, submit any pending ops that no
+    /// longer conflict with anything in-flight, and distribute `res`
+    /// across the op's segments if it was a vectored read.
+    fn complete(&mut self, idx: usize, res: i64) -> Completion {
+        let op = self.ops.freeidx(idx);
+        self.overlap.remove(op.fd, op.start, idx);
+        let seg_lens = op.seg_lens.map(|lens| distribute(if res > 0 { res as usize } else { 0 }, &lens));
+
+        let pending = mem::replace(&mut self.pending, Vec::new());
+        let tuples = pending.into_iter().map(|pidx| {
+            let op = &self.ops[pidx];
+            (pidx, op.fd, op.start, op.end, op.write)
+        }).collect();
+        // `partition_pending` already reserved each ready op's range
+        // in the overlap tracker (in queue order, so two pending ops
+        // that conflict with each other are released one at a time
+        // rather than together); actually hand them to the kernel
+        // here, undoing the reservation if that fails.
+        let (ready, still_pending) = partition_pending(&mut self.overlap, tuples);
+        for pidx in ready {
+            if self.do_submit(pidx).is_err() {
+                let op = &self.ops[pidx];
+                let (fd, start) = (op.fd, op.start);
+                self.overlap.remove(fd, start, pidx);
+                self.ops.freeidx(pidx);
+            }
+        }
+        self.pending = still_pending.into_iter().map(|(pidx, _, _, _, _)| pidx).collect();
+
+        Completion { idx: idx, res: res, seg_lens: seg_lens }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { aioabi::io_destroy(self.ctx); }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate libc;
+    use super::{OverlapTracker, partition_pending, distribute, Context};
+
+    #[test]
+    fn overlapping_writes_conflict() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, true);
+        assert!(t.conflicts(3, 5, 15, true));
+    }
+
+    #[test]
+    fn adjacent_ranges_dont_conflict() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, true);
+        assert!(!t.conflicts(3, 10, 20, true));
+        assert!(!t.conflicts(3, 10, 20, false));
+    }
+
+    #[test]
+    fn disjoint_ranges_dont_conflict() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, true);
+        assert!(!t.conflicts(3, 20, 30, true));
+    }
+
+    #[test]
+    fn different_fds_dont_conflict() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, true);
+        assert!(!t.conflicts(4, 0, 10, true));
+    }
+
+    #[test]
+    fn read_conflicts_with_inflight_write() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, true);
+        assert!(t.conflicts(3, 5, 15, false));
+    }
+
+    #[test]
+    fn write_conflicts_with_inflight_read() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, false);
+        assert!(t.conflicts(3, 5, 15, true));
+    }
+
+    #[test]
+    fn overlapping_reads_dont_conflict() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, false);
+        assert!(!t.conflicts(3, 5, 15, false));
+    }
+
+    #[test]
+    fn remove_clears_the_conflict() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, true);
+        assert!(t.conflicts(3, 5, 15, true));
+        t.remove(3, 0, 1);
+        assert!(!t.conflicts(3, 5, 15, true));
+    }
+
+    #[test]
+    fn same_start_ops_are_tracked_independently() {
+        // Two in-flight reads sharing a start offset (idx 1 and idx
+        // 2) must each get their own map entry; removing one must not
+        // clobber the other's.
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, false);
+        t.insert(3, 0, 2, 20, false);
+
+        t.remove(3, 0, 1);
+        // idx 2's longer read, [0, 20), must still be tracked, so a
+        // new write over it is made to wait rather than racing it.
+        assert!(t.conflicts(3, 0, 30, true));
+
+        t.remove(3, 0, 2);
+        assert!(!t.conflicts(3, 0, 30, true));
+    }
+
+    #[test]
+    fn pending_op_released_once_conflict_clears() {
+        let mut t = OverlapTracker::new();
+        t.insert(3, 0, 1, 10, true);
+
+        // A write queued behind the in-flight write at [0, 10).
+        let pending = vec![(7, 3, 5, 15, true)];
+        let (ready, still_pending) = partition_pending(&mut t, pending);
+        assert_eq!(ready, Vec::<usize>::new());
+        assert_eq!(still_pending, vec![(7, 3, 5, 15, true)]);
+
+        // Once the conflicting write completes and is removed, the
+        // same pending op is ready to go.
+        t.remove(3, 0, 1);
+        let (ready, still_pending) = partition_pending(&mut t, still_pending);
+        assert_eq!(ready, vec![7]);
+        assert!(still_pending.is_empty());
+    }
+
+    #[test]
+    fn pending_ops_conflicting_with_each_other_release_one_at_a_time() {
+        // Two pending writes that both overlap the write that just
+        // completed, and also overlap each other: releasing them in
+        // one pass must not hand both to the kernel at once, or the
+        // serialization this module exists for is defeated.
+        let mut t = OverlapTracker::new();
+        let pending = vec![(7, 3, 0, 10, true), (8, 3, 0, 10, true)];
+        let (ready, still_pending) = partition_pending(&mut t, pending);
+        assert_eq!(ready, vec![7]);
+        assert_eq!(still_pending, vec![(8, 3, 0, 10, true)]);
+        // The first release must have reserved its range, so the
+        // second is correctly still seen as conflicting.
+        assert!(t.conflicts(3, 0, 10, true));
+
+        t.remove(3, 0, 7);
+        let (ready, still_pending) = partition_pending(&mut t, still_pending);
+        assert_eq!(ready, vec![8]);
+        assert!(still_pending.is_empty());
+    }
+
+    #[test]
+    fn distribute_exact() {
+        assert_eq!(distribute(12, &[4, 8]), vec![4, 8]);
+    }
+
+    #[test]
+    fn distribute_short_read() {
+        // The kernel fills segments in order, so a short read leaves
+        // a fully-filled prefix, one partially-filled segment, and a
+        // fully-unfilled remainder.
+        assert_eq!(distribute(5, &[4, 8, 4]), vec![4, 1, 0]);
+    }
+
+    #[test]
+    fn distribute_zero() {
+        assert_eq!(distribute(0, &[4, 8]), vec![0, 0]);
+    }
+
+    #[test]
+    fn resfd_counter_roundtrip() {
+        let fd = unsafe { libc::eventfd(0, 0) };
+        assert!(fd >= 0);
+        let val: u64 = 5;
+        let rc = unsafe {
+            libc::write(fd, &val as *const u64 as *const libc::c_void, ::std::mem::size_of::<u64>())
+        };
+        assert_eq!(rc, ::std::mem::size_of::<u64>() as isize);
+        assert_eq!(Context::read_resfd_counter(fd).unwrap(), 5);
+        unsafe { libc::close(fd); }
+    }
+}